@@ -1,12 +1,13 @@
 extern crate ring;
 extern crate udt;
+extern crate untrusted;
 
 use std::net::{UdpSocket, SocketAddr, IpAddr};
 use std::str;
 use std::fmt;
+use std::thread;
+use std::time::Duration;
 use udt::{UdtSocket, UdtError, UdtOpts, SocketType, SocketFamily};
-use ring::aead;
-use ring::rand;
 
 // TODO config
 const UDT_BUF_SIZE: i32 = 4096000;
@@ -15,14 +16,86 @@ pub const MAX_MESSAGE_SIZE: usize = 1024000;
 pub mod crypto {
     use ring::aead;
     use ring::aead::{SealingKey, OpeningKey, Algorithm};
+    use ring::digest;
+    use ring::hkdf;
+    use ring::hmac::SigningKey;
     use ring::rand::{SystemRandom, SecureRandom};
+
+    // Sizes the keys from `gen_key`/`key_len` and is used by the
+    // raw-primitive tests/benches below; `Handler` itself picks its
+    // algorithm per-instance via `CryptoMethod`.
     static ALGORITHM: &'static Algorithm = &aead::AES_256_GCM;
 
+    // How many sent/received sequence numbers we'll tolerate being
+    // out-of-order before a datagram is rejected as too old.
+    const REPLAY_WINDOW: u64 = 64;
+
+    // Ratchet the key after this many messages or this many bytes sealed,
+    // whichever comes first.
+    const DEFAULT_REKEY_MESSAGES: u64 = 1_000_000;
+    const DEFAULT_REKEY_BYTES: u64 = 1 << 30;
+
+    // Prepended to every sealed message, ahead of the nonce: signals to the
+    // peer that the sender just ratcheted, so the receiver ratchets in lock
+    // step before attempting to open this (and all following) messages.
+    const REKEY_FLAG_LEN: usize = 1;
+
+    // Wire framing version. `open` rejects anything else outright.
+    pub const VERSION: u8 = 1;
+    const VERSION_LEN: usize = 1;
+    const METHOD_LEN: usize = 1;
+
+    // The two AEADs `Handler` can speak. Negotiated during the handshake so
+    // machines without AES hardware acceleration aren't stuck paying its
+    // software-fallback cost.
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum CryptoMethod {
+        Aes256Gcm,
+        ChaCha20Poly1305,
+    }
+
+    impl CryptoMethod {
+        fn algorithm(&self) -> &'static Algorithm {
+            match *self {
+                CryptoMethod::Aes256Gcm => &aead::AES_256_GCM,
+                CryptoMethod::ChaCha20Poly1305 => &aead::CHACHA20_POLY1305,
+            }
+        }
+
+        pub fn to_byte(&self) -> u8 {
+            match *self {
+                CryptoMethod::Aes256Gcm => 0,
+                CryptoMethod::ChaCha20Poly1305 => 1,
+            }
+        }
+
+        pub fn from_byte(b: u8) -> Option<CryptoMethod> {
+            match b {
+                0 => Some(CryptoMethod::Aes256Gcm),
+                1 => Some(CryptoMethod::ChaCha20Poly1305),
+                _ => None,
+            }
+        }
+    }
+
     pub struct Handler {
         _working_buf: [u8; super::MAX_MESSAGE_SIZE],
-        rand: SystemRandom,
+        method: CryptoMethod,
         opening_key: OpeningKey,
         sealing_key: SealingKey,
+        opening_key_material: Vec<u8>,
+        sealing_key_material: Vec<u8>,
+
+        send_seq: u64,
+        sent_messages: u64,
+        sent_bytes: u64,
+
+        recv_highest: u64,
+        recv_window: u64,
+        recv_started: bool,
+
+        rekey_after_messages: u64,
+        rekey_after_bytes: u64,
     }
 
     pub fn gen_key() -> Vec<u8> {
@@ -32,28 +105,124 @@ pub mod crypto {
         keybytes
     }
 
+    pub fn key_len() -> usize {
+        ALGORITHM.key_len()
+    }
+
     impl Handler {
+        // Seals and opens with the *same* key and both counters starting at
+        // 0, so two peers each built via `new` from the same shared key
+        // would reuse (key, nonce) across directions -- catastrophic for a
+        // counter-nonce AEAD. That's only safe for a single `Handler`
+        // looping a message back to itself, which is all this is for; real
+        // two-party sessions must go through `from_keys` with the peer's
+        // independently-derived directional halves, e.g. from
+        // `handshake::Handshake::run`. `#[cfg(test)]` keeps it out of the
+        // library's public API so it can't be reached for anything else.
+        #[cfg(test)]
         pub fn new(key: &[u8]) -> Handler {
+            Handler::from_keys(key, key, CryptoMethod::Aes256Gcm)
+        }
+
+        // Builds a Handler from a pair of directional keys, e.g. ones derived
+        // and negotiated by `handshake::Handshake::run` rather than a single
+        // shared secret with a fixed algorithm.
+        pub fn from_keys(opening_key: &[u8], sealing_key: &[u8], method: CryptoMethod) -> Handler {
+            let algorithm = method.algorithm();
             Handler {
                 _working_buf: [0u8; super::MAX_MESSAGE_SIZE],
-                rand: SystemRandom::new(),
-                opening_key: aead::OpeningKey::new(ALGORITHM, key).unwrap(),
-                sealing_key: aead::SealingKey::new(ALGORITHM, key).unwrap(),
+                method: method,
+                opening_key: aead::OpeningKey::new(algorithm, opening_key).unwrap(),
+                sealing_key: aead::SealingKey::new(algorithm, sealing_key).unwrap(),
+                opening_key_material: opening_key.to_owned(),
+                sealing_key_material: sealing_key.to_owned(),
+                send_seq: 0,
+                sent_messages: 0,
+                sent_bytes: 0,
+                recv_highest: 0,
+                recv_window: 0,
+                recv_started: false,
+                rekey_after_messages: DEFAULT_REKEY_MESSAGES,
+                rekey_after_bytes: DEFAULT_REKEY_BYTES,
             }
         }
 
+        // Overrides how often `seal` ratchets the sealing key; exposed mainly
+        // so tests don't have to push a gigabyte of traffic to exercise it.
+        pub fn set_rekey_thresholds(&mut self, messages: u64, bytes: u64) {
+            self.rekey_after_messages = messages;
+            self.rekey_after_bytes = bytes;
+        }
+
+        // How many bytes `seal` adds on top of the plaintext: the wire
+        // header plus the AEAD's authentication tag. Callers size their
+        // scratch buffers off this instead of hard-coding either.
+        pub fn overhead(&self) -> usize {
+            let algorithm = self.method.algorithm();
+            VERSION_LEN + METHOD_LEN + REKEY_FLAG_LEN + algorithm.nonce_len() + algorithm.max_overhead_len()
+        }
+
+        fn write_nonce(nonce: &mut [u8], seq: u64) {
+            let nonce_len = nonce.len();
+            for b in nonce.iter_mut() {
+                *b = 0;
+            }
+            let seq_bytes = [
+                (seq >> 56) as u8, (seq >> 48) as u8, (seq >> 40) as u8, (seq >> 32) as u8,
+                (seq >> 24) as u8, (seq >> 16) as u8, (seq >> 8) as u8, seq as u8,
+            ];
+            let start = nonce_len - seq_bytes.len();
+            nonce[start..].copy_from_slice(&seq_bytes);
+        }
+
+        fn ratchet(key_material: &[u8]) -> Vec<u8> {
+            let salt = SigningKey::new(&digest::SHA256, &[]);
+            let mut next = vec![0u8; key_material.len()];
+            hkdf::extract_and_expand(&salt, key_material, b"shoop rekey v1", &mut next);
+            next
+        }
+
+        fn rekey_sealing(&mut self) {
+            self.sealing_key_material = Handler::ratchet(&self.sealing_key_material);
+            self.sealing_key = aead::SealingKey::new(self.method.algorithm(), &self.sealing_key_material).unwrap();
+            self.send_seq = 0;
+            self.sent_messages = 0;
+            self.sent_bytes = 0;
+        }
+
         pub fn seal(&mut self, buf: &mut [u8], len: usize) -> Result<usize, ()> {
-            let nonce_len = ALGORITHM.nonce_len();
-            let max_suffix_len = ALGORITHM.max_overhead_len();
+            let algorithm = self.method.algorithm();
+            let nonce_len = algorithm.nonce_len();
+            let max_suffix_len = algorithm.max_overhead_len();
+            let header_len = VERSION_LEN + METHOD_LEN + REKEY_FLAG_LEN + nonce_len;
 
             assert!(nonce_len < u8::max_value() as usize,
                     "Uh, why is the nonce size this big?");
 
-            assert!(len <= buf.len() - max_suffix_len,
+            assert!(len <= buf.len() - max_suffix_len - header_len,
                     "Buffer doesn't have enough suffix padding.");
 
+            // If the previous message crossed a rekey threshold, ratchet now
+            // so this message (and everything after it) goes out under the
+            // new key, with the flag telling the peer to follow along.
+            let rekeying = self.sent_messages >= self.rekey_after_messages ||
+                           self.sent_bytes >= self.rekey_after_bytes;
+            if rekeying {
+                self.rekey_sealing();
+            }
+
             let mut nonce = vec![0u8; nonce_len];
-            self.rand.fill(&mut nonce).unwrap();
+            Handler::write_nonce(&mut nonce, self.send_seq);
+
+            // Built up front so it can be bound into the AEAD as associated
+            // data below -- that's what makes the version/method/rekey flag
+            // tamper-evident instead of being free for an attacker to flip.
+            let mut header = vec![0u8; header_len];
+            header[0] = VERSION;
+            header[VERSION_LEN] = self.method.to_byte();
+            header[VERSION_LEN + METHOD_LEN] = if rekeying { 1 } else { 0 };
+            let nonce_start = VERSION_LEN + METHOD_LEN + REKEY_FLAG_LEN;
+            header[nonce_start..header_len].copy_from_slice(&nonce[..]);
 
             let mut sealed = vec![0u8; len + max_suffix_len];
             sealed[0..len].copy_from_slice(&buf[..len]);
@@ -61,31 +230,135 @@ pub mod crypto {
                                       &nonce,
                                       &mut sealed,
                                       max_suffix_len,
-                                      &[]) {
+                                      &header) {
                 Ok(seal_len) => {
-                    buf[..nonce_len].copy_from_slice(&nonce[..]);
-                    buf[nonce_len..nonce_len+seal_len].copy_from_slice(&sealed[..seal_len]);
-                    Ok(nonce_len + seal_len)
+                    buf[..header_len].copy_from_slice(&header);
+                    buf[header_len..header_len+seal_len].copy_from_slice(&sealed[..seal_len]);
+
+                    self.send_seq += 1;
+                    self.sent_messages += 1;
+                    self.sent_bytes += seal_len as u64;
+
+                    Ok(header_len + seal_len)
                 }
-                Err(e) => {
+                Err(_) => {
                     Err(())
                 }
             }
         }
 
-        pub fn open(&mut self, buf: &mut [u8]) -> Result<usize, String> {
-            let nonce_len = ALGORITHM.nonce_len();
+        // Tracks `seq` against the sliding replay window, returning `false`
+        // for anything already seen or too far behind the highest sequence.
+        fn accept_sequence(&mut self, seq: u64) -> bool {
+            if !self.recv_started {
+                self.recv_started = true;
+                self.recv_highest = seq;
+                self.recv_window = 1;
+                return true;
+            }
+
+            if seq > self.recv_highest {
+                let advance = seq - self.recv_highest;
+                if advance >= REPLAY_WINDOW {
+                    self.recv_window = 0;
+                } else {
+                    self.recv_window <<= advance;
+                }
+                self.recv_window |= 1;
+                self.recv_highest = seq;
+                return true;
+            }
+
+            let age = self.recv_highest - seq;
+            if age >= REPLAY_WINDOW {
+                return false;
+            }
+
+            let bit = 1u64 << age;
+            if self.recv_window & bit != 0 {
+                return false;
+            }
+            self.recv_window |= bit;
+            true
+        }
 
-            if buf.len() < nonce_len {
-                return Err("msg not long enough to contain nonce".into());
-            } else if buf.len() > super::MAX_MESSAGE_SIZE {
+        pub fn open(&mut self, buf: &mut [u8]) -> Result<usize, String> {
+            let algorithm = self.method.algorithm();
+            let nonce_len = algorithm.nonce_len();
+            let header_len = VERSION_LEN + METHOD_LEN + REKEY_FLAG_LEN + nonce_len;
+
+            if buf.len() < header_len {
+                return Err("msg not long enough to contain header".into());
+            } else if buf.len() > super::MAX_MESSAGE_SIZE + self.overhead() {
+                // `buf` here is the sealed message, which is `overhead()`
+                // bytes larger than the plaintext `seal` was given -- the
+                // ceiling has to account for that or a maximum-size message
+                // seal() happily produces gets rejected the moment it's
+                // opened.
                 return Err("max message size exceeded".into());
             }
 
-            let nonce = buf[..nonce_len].to_owned();
+            if buf[0] != VERSION {
+                return Err(format!("unsupported wire version {}", buf[0]));
+            }
+
+            let method = try!(CryptoMethod::from_byte(buf[VERSION_LEN])
+                .ok_or_else(|| "unrecognized crypto method".to_string()));
+            if method != self.method {
+                return Err("message sealed with a different cipher than negotiated".into());
+            }
+
+            // The rekey flag and nonce live in the header alongside the
+            // AEAD tag that covers them as associated data below, so
+            // neither is trustworthy yet -- we just read them out to know
+            // what key/nonce to *try*.
+            let rekeying = buf[VERSION_LEN + METHOD_LEN] == 1;
+            let nonce_start = VERSION_LEN + METHOD_LEN + REKEY_FLAG_LEN;
+            let nonce = buf[nonce_start..header_len].to_owned();
+            let header = buf[..header_len].to_owned();
+
+            // If the header claims a rekey, don't ratchet yet: derive the
+            // candidate next key and attempt decryption under it first. A
+            // forged or replayed datagram with the flag set must not be
+            // able to desynchronize the real key schedule, so the ratchet
+            // (and the replay-window reset below) only commits once the
+            // AEAD tag proves the message was actually sealed under it.
+            let candidate = if rekeying {
+                let material = Handler::ratchet(&self.opening_key_material);
+                let key = try!(aead::OpeningKey::new(algorithm, &material)
+                    .map_err(|_| "key derivation failed".to_string()));
+                Some((key, material))
+            } else {
+                None
+            };
+
+            let plaintext_len = {
+                let opening_key = match candidate {
+                    Some((ref key, _)) => key,
+                    None => &self.opening_key,
+                };
+                try!(aead::open_in_place(opening_key, &nonce, header_len, buf, &header)
+                    .map_err(|_| "decrypt failed".to_string()))
+            };
+
+            if let Some((key, material)) = candidate {
+                self.opening_key = key;
+                self.opening_key_material = material;
+                self.recv_highest = 0;
+                self.recv_window = 0;
+                self.recv_started = false;
+            }
+
+            let mut seq = 0u64;
+            for &b in &nonce[nonce.len() - 8..] {
+                seq = (seq << 8) | b as u64;
+            }
 
-            aead::open_in_place(&self.opening_key, &nonce, nonce_len, buf, &[])
-                .map_err(|_| String::from("decrypt failed"))
+            if !self.accept_sequence(seq) {
+                return Err("duplicate or too-old sequence number".into());
+            }
+
+            Ok(plaintext_len)
         }
     }
 
@@ -145,6 +418,57 @@ pub mod crypto {
             assert_eq!(orig, &data[..decrypted_len], "original and decrypted don't match!");
         }
 
+        // A maximum-size plaintext message must round-trip: the sealed form
+        // runs `overhead()` bytes past MAX_MESSAGE_SIZE, and `open` has to
+        // accept that rather than rejecting it as over the size limit.
+        #[test]
+        fn max_message_size_boundary() {
+            let key = super::gen_key();
+            let mut handler = super::Handler::new(&key);
+            let data_size = super::super::MAX_MESSAGE_SIZE;
+            let mut buf = vec![0u8; data_size + handler.overhead()];
+            for i in 0..data_size {
+                buf[i] = (i % 251) as u8;
+            }
+            let orig = buf[..data_size].to_owned();
+
+            let cipher_len = handler.seal(&mut buf, data_size).unwrap();
+            let decrypted_len = handler.open(&mut buf[..cipher_len]).unwrap();
+            assert_eq!(decrypted_len, data_size);
+            assert_eq!(orig, &buf[..decrypted_len]);
+        }
+
+        #[test]
+        fn rekey_and_replay_window() {
+            let key = super::gen_key();
+            let mut handler = super::Handler::new(&key);
+            handler.set_rekey_thresholds(3, u64::max_value());
+
+            let mut sealed = Vec::new();
+            for i in 0..5 {
+                let mut buf = vec![0u8; super::super::MAX_MESSAGE_SIZE];
+                buf[0] = i as u8;
+                let len = handler.seal(&mut buf, 1).unwrap();
+                sealed.push((buf, len));
+            }
+
+            // Messages open in order, including across the rekey boundary
+            // triggered by the threshold above.
+            for (i, &(ref buf, len)) in sealed.iter().enumerate() {
+                let mut copy = buf.clone();
+                let decrypted_len = handler.open(&mut copy[..len]).unwrap();
+                assert_eq!(decrypted_len, 1);
+                assert_eq!(copy[0], i as u8);
+            }
+
+            // Replaying an already-seen message must be rejected. The last
+            // one was sealed under the current key, so it decrypts and is
+            // turned away by the replay window itself.
+            let (ref buf, len) = sealed[4];
+            let mut replay = buf.clone();
+            assert!(handler.open(&mut replay[..len]).is_err());
+        }
+
         #[test]
         fn key_sanity() {
             use std::collections::HashSet;
@@ -232,36 +556,586 @@ pub mod crypto {
     }
 }
 
-fn new_udt_socket() -> UdtSocket {
+// Replaces the out-of-band shared key with a mutual, authenticated
+// key-exchange run over the UDT socket once it's connected/accepted.
+pub mod handshake {
+    use std::collections::HashSet;
+    use ring::agreement::{self, EphemeralPrivateKey, X25519};
+    use ring::digest;
+    use ring::hkdf;
+    use ring::hmac::SigningKey;
+    use ring::rand::{SystemRandom, SecureRandom};
+    use ring::signature::{self, Ed25519KeyPair};
+    use udt::{UdtSocket, UdtError};
+    use untrusted;
+    use super::crypto;
+
+    pub const PUBLIC_KEY_LEN: usize = 32;
+    const SIGNATURE_LEN: usize = 64;
+
+    #[derive(Clone, PartialEq, Eq, Hash, Debug)]
+    pub struct PublicKey(pub [u8; PUBLIC_KEY_LEN]);
+
+    // A node's long-term static key pair, used to sign (not derive) each
+    // session's ephemeral key. In `SharedSecret` trust both ends derive the
+    // same `Identity` (and thus the same `PublicKey`) from a passphrase; in
+    // `Explicit` trust each node generates its own at random.
+    pub struct Identity {
+        signing_key: Ed25519KeyPair,
+        pub public_key: PublicKey,
+    }
+
+    impl Identity {
+        pub fn generate() -> Result<Identity, HandshakeError> {
+            let rng = SystemRandom::new();
+            let mut seed = [0u8; PUBLIC_KEY_LEN];
+            try!(rng.fill(&mut seed).map_err(|_| HandshakeError::KeyGeneration));
+            Identity::from_seed(&seed)
+        }
+
+        // Deterministic identity for "shared secret" trust: both sides hash
+        // the passphrase down to a seed and regenerate the same key pair.
+        pub fn from_passphrase(passphrase: &str) -> Result<Identity, HandshakeError> {
+            let seed = digest::digest(&digest::SHA256, passphrase.as_bytes());
+            Identity::from_seed(seed.as_ref())
+        }
+
+        fn from_seed(seed: &[u8]) -> Result<Identity, HandshakeError> {
+            let signing_key = try!(Ed25519KeyPair::from_seed_unchecked(untrusted::Input::from(seed))
+                .map_err(|_| HandshakeError::KeyGeneration));
+            let mut public_key = [0u8; PUBLIC_KEY_LEN];
+            public_key.copy_from_slice(signing_key.public_key_bytes());
+            Ok(Identity { signing_key: signing_key, public_key: PublicKey(public_key) })
+        }
+    }
+
+    fn public_key_of(private_key: &EphemeralPrivateKey) -> Result<PublicKey, HandshakeError> {
+        let mut bytes = [0u8; PUBLIC_KEY_LEN];
+        try!(agreement::compute_public_key(private_key, &mut bytes)
+            .map_err(|_| HandshakeError::KeyGeneration));
+        Ok(PublicKey(bytes))
+    }
+
+    // Who we're willing to talk to, given our own identity.
+    pub enum Trust {
+        // Both ends derived their identity from the same passphrase, so the
+        // one trusted peer is whoever else derived that same identity.
+        // `beacon_key` is a second value independently derived from the
+        // same passphrase, kept apart from the static identity so it can be
+        // used as an HMAC key for beacon tokens without handing out a
+        // secret that's also ever transmitted on the wire.
+        SharedSecret { peer: PublicKey, beacon_key: Vec<u8> },
+        // Each peer's static public key is configured out of band. There's
+        // no secret shared between peers in this mode, so beacons -- which
+        // need a symmetric key both publisher and fetcher already know --
+        // aren't supported.
+        Explicit { peers: HashSet<PublicKey> },
+    }
+
+    impl Trust {
+        pub fn shared_secret(passphrase: &str) -> Result<Trust, HandshakeError> {
+            let identity = try!(Identity::from_passphrase(passphrase));
+            Ok(Trust::SharedSecret { peer: identity.public_key, beacon_key: derive_beacon_key(passphrase) })
+        }
+
+        pub fn explicit(peers: HashSet<PublicKey>) -> Trust {
+            Trust::Explicit { peers: peers }
+        }
+
+        fn trusts(&self, candidate: &PublicKey) -> bool {
+            match *self {
+                Trust::SharedSecret { ref peer, .. } => peer == candidate,
+                Trust::Explicit { ref peers } => peers.contains(candidate),
+            }
+        }
+
+        // The HMAC key used to authenticate this node's published beacons.
+        // Only meaningful in `SharedSecret` trust: `Explicit` trust has no
+        // value both a publishing `Server` and a fetching `Client` agree on
+        // out of band that isn't also the (public) static identity key.
+        pub fn beacon_key(&self) -> Result<Vec<u8>, HandshakeError> {
+            match *self {
+                Trust::SharedSecret { ref beacon_key, .. } => Ok(beacon_key.clone()),
+                Trust::Explicit { .. } => Err(HandshakeError::NoSharedSecret),
+            }
+        }
+    }
+
+    // Derived from the passphrase via HKDF under its own context, while the
+    // identity seed is a plain SHA-256 of it, so the beacon key and the
+    // static signing key are cryptographically independent despite sharing
+    // a source passphrase.
+    fn derive_beacon_key(passphrase: &str) -> Vec<u8> {
+        let salt = SigningKey::new(&digest::SHA256, &[]);
+        let mut okm = vec![0u8; crypto::key_len()];
+        hkdf::extract_and_expand(&salt, passphrase.as_bytes(), b"shoop beacon v1", &mut okm);
+        okm
+    }
+
+    #[derive(Debug)]
+    pub enum HandshakeError {
+        Io(UdtError),
+        KeyGeneration,
+        UntrustedPeer,
+        KeyAgreement,
+        // Requested a beacon key from `Trust::Explicit`, which has no value
+        // shared between peers to key one with.
+        NoSharedSecret,
+    }
+
+    impl From<UdtError> for HandshakeError {
+        fn from(e: UdtError) -> HandshakeError {
+            HandshakeError::Io(e)
+        }
+    }
+
+    // Runs once over a freshly connected/accepted `UdtSocket`. Each side
+    // sends its ephemeral public key signed by its static identity, checks
+    // the peer's static key against `trust` and its signature over the
+    // peer's ephemeral key, performs DH on the ephemeral keys, and derives a
+    // `crypto::Handler` from the result via HKDF.
+    pub struct Handshake<'a> {
+        identity: &'a Identity,
+        trust: &'a Trust,
+        initiator: bool,
+    }
+
+    impl<'a> Handshake<'a> {
+        // `initiator` picks which side's key derives the sealing vs. opening
+        // half of the session, so `Client::connect` passes `true` and
+        // `Server::accept` passes `false`.
+        pub fn new(identity: &'a Identity, trust: &'a Trust, initiator: bool) -> Handshake<'a> {
+            Handshake { identity: identity, trust: trust, initiator: initiator }
+        }
+
+        pub fn run(&self, sock: &UdtSocket) -> Result<crypto::Handler, HandshakeError> {
+            let rng = SystemRandom::new();
+            let ephemeral = try!(EphemeralPrivateKey::generate(&X25519, &rng)
+                .map_err(|_| HandshakeError::KeyGeneration));
+            let ephemeral_public = try!(public_key_of(&ephemeral));
+            let local_aes_accel = has_aes_acceleration();
+
+            // Prove we hold the static identity's private key by signing our
+            // fresh ephemeral public key *and* the cipher negotiation byte
+            // with it. Without this, an attacker could pair a trusted
+            // peer's (public) static key with an ephemeral key of its own
+            // and pass the `trust.trusts` check below -- a full
+            // man-in-the-middle. Covering the accel byte too means it can't
+            // be flipped in transit to force a cipher mismatch either.
+            let mut signed = [0u8; PUBLIC_KEY_LEN + 1];
+            signed[..PUBLIC_KEY_LEN].copy_from_slice(&ephemeral_public.0);
+            signed[PUBLIC_KEY_LEN] = if local_aes_accel { 1 } else { 0 };
+            let signature = self.identity.signing_key.sign(&signed);
+
+            let mut outgoing = [0u8; PUBLIC_KEY_LEN * 2 + SIGNATURE_LEN + 1];
+            outgoing[..PUBLIC_KEY_LEN].copy_from_slice(&ephemeral_public.0);
+            outgoing[PUBLIC_KEY_LEN..PUBLIC_KEY_LEN * 2].copy_from_slice(&self.identity.public_key.0);
+            outgoing[PUBLIC_KEY_LEN * 2..PUBLIC_KEY_LEN * 2 + SIGNATURE_LEN].copy_from_slice(signature.as_ref());
+            outgoing[PUBLIC_KEY_LEN * 2 + SIGNATURE_LEN] = signed[PUBLIC_KEY_LEN];
+            try!(sock.sendmsg(&outgoing));
+
+            let mut incoming = [0u8; PUBLIC_KEY_LEN * 2 + SIGNATURE_LEN + 1];
+            let len = try!(sock.recvmsg(&mut incoming));
+            if len != incoming.len() {
+                return Err(HandshakeError::KeyAgreement);
+            }
+            let mut peer_ephemeral = [0u8; PUBLIC_KEY_LEN];
+            peer_ephemeral.copy_from_slice(&incoming[..PUBLIC_KEY_LEN]);
+            let mut peer_static = [0u8; PUBLIC_KEY_LEN];
+            peer_static.copy_from_slice(&incoming[PUBLIC_KEY_LEN..PUBLIC_KEY_LEN * 2]);
+            let peer_static = PublicKey(peer_static);
+            let peer_signature = &incoming[PUBLIC_KEY_LEN * 2..PUBLIC_KEY_LEN * 2 + SIGNATURE_LEN];
+            let peer_accel_byte = incoming[PUBLIC_KEY_LEN * 2 + SIGNATURE_LEN];
+            let peer_aes_accel = peer_accel_byte == 1;
+
+            if !self.trust.trusts(&peer_static) {
+                return Err(HandshakeError::UntrustedPeer);
+            }
+
+            // This is what actually authenticates the handshake: the
+            // signature binds the peer's ephemeral key -- and its cipher
+            // negotiation byte, so that can't be tampered with either --
+            // to its static identity, proving whoever sent it holds the
+            // matching private key. `trust.trusts` above only recognizes
+            // the static key -- by itself it proves nothing about who sent
+            // this message, since static keys are public and travel in the
+            // clear.
+            let mut peer_signed = [0u8; PUBLIC_KEY_LEN + 1];
+            peer_signed[..PUBLIC_KEY_LEN].copy_from_slice(&peer_ephemeral);
+            peer_signed[PUBLIC_KEY_LEN] = peer_accel_byte;
+            try!(signature::verify(&signature::ED25519,
+                                    untrusted::Input::from(&peer_static.0),
+                                    untrusted::Input::from(&peer_signed),
+                                    untrusted::Input::from(peer_signature))
+                .map_err(|_| HandshakeError::UntrustedPeer));
+
+            // Prefer ChaCha20-Poly1305 unless both ends report AES hardware
+            // acceleration, so neither side is stuck paying for a software
+            // AES fallback.
+            let method = if local_aes_accel && peer_aes_accel {
+                crypto::CryptoMethod::Aes256Gcm
+            } else {
+                crypto::CryptoMethod::ChaCha20Poly1305
+            };
+
+            let peer_ephemeral_input = untrusted::Input::from(&peer_ephemeral);
+            agreement::agree_ephemeral(ephemeral,
+                                        &X25519,
+                                        peer_ephemeral_input,
+                                        HandshakeError::KeyAgreement,
+                                        |shared_secret| {
+                let salt = SigningKey::new(&digest::SHA256, &[]);
+                let mut okm = vec![0u8; crypto::key_len() * 2];
+                hkdf::extract_and_expand(&salt, shared_secret, b"shoop handshake v1", &mut okm);
+
+                // Each direction gets its own half of the HKDF output; which
+                // half is sealing vs. opening depends on which side we are so
+                // both peers end up agreeing with each other.
+                let (first, second) = okm.split_at(crypto::key_len());
+                let handler = if self.initiator {
+                    crypto::Handler::from_keys(second, first, method)
+                } else {
+                    crypto::Handler::from_keys(first, second, method)
+                };
+                Ok(handler)
+            })
+        }
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn has_aes_acceleration() -> bool {
+        is_x86_feature_detected!("aes")
+    }
+
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+    fn has_aes_acceleration() -> bool {
+        false
+    }
+}
+
+// Lets a `Server` behind NAT publish where it can be reached without a fixed,
+// publicly routable address: the endpoint is packed, authenticated under a
+// shared secret, and base62-encoded into a short token a `Client` can be
+// handed instead of a `SocketAddr`.
+pub mod beacon {
+    use std::fs::File;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, IpAddr, Ipv4Addr, Ipv6Addr, TcpStream};
+    use ring::digest;
+    use ring::hmac;
+
+    const ALPHABET: &'static [u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+    const TAG_LEN: usize = 8;
+
+    #[derive(Debug)]
+    pub enum BeaconError {
+        Malformed,
+        InvalidTag,
+        Io(String),
+    }
+
+    fn addr_to_bytes(addr: &SocketAddr) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        match addr.ip() {
+            IpAddr::V4(v4) => {
+                bytes.push(4);
+                bytes.extend_from_slice(&v4.octets());
+            }
+            IpAddr::V6(v6) => {
+                bytes.push(6);
+                bytes.extend_from_slice(&v6.octets());
+            }
+        }
+        bytes.push((addr.port() >> 8) as u8);
+        bytes.push(addr.port() as u8);
+        bytes
+    }
+
+    fn bytes_to_addr(bytes: &[u8]) -> Result<SocketAddr, BeaconError> {
+        match bytes.first() {
+            Some(&4) if bytes.len() == 1 + 4 + 2 => {
+                let ip = Ipv4Addr::new(bytes[1], bytes[2], bytes[3], bytes[4]);
+                let port = ((bytes[5] as u16) << 8) | bytes[6] as u16;
+                Ok(SocketAddr::new(IpAddr::V4(ip), port))
+            }
+            Some(&6) if bytes.len() == 1 + 16 + 2 => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&bytes[1..17]);
+                let port = ((bytes[17] as u16) << 8) | bytes[18] as u16;
+                Ok(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port))
+            }
+            _ => Err(BeaconError::Malformed),
+        }
+    }
+
+    fn tag(key: &[u8], payload: &[u8]) -> Vec<u8> {
+        let signing_key = hmac::SigningKey::new(&digest::SHA256, key);
+        hmac::sign(&signing_key, payload).as_ref()[..TAG_LEN].to_owned()
+    }
+
+    // Treats `bytes` as one big-endian integer and repeatedly divides by 62
+    // to pull off base62 digits, least-significant first.
+    fn base62_encode(mut bytes: Vec<u8>) -> String {
+        let mut digits = Vec::new();
+        while bytes.iter().any(|&b| b != 0) {
+            let mut remainder = 0u32;
+            for b in bytes.iter_mut() {
+                let acc = (remainder << 8) | *b as u32;
+                *b = (acc / 62) as u8;
+                remainder = acc % 62;
+            }
+            digits.push(ALPHABET[remainder as usize]);
+        }
+        if digits.is_empty() {
+            digits.push(ALPHABET[0]);
+        }
+        digits.reverse();
+        String::from_utf8(digits).unwrap()
+    }
+
+    fn base62_decode(s: &str) -> Result<Vec<u8>, BeaconError> {
+        let mut bytes: Vec<u8> = vec![0];
+        for c in s.bytes() {
+            let digit = try!(ALPHABET.iter().position(|&a| a == c).ok_or(BeaconError::Malformed)) as u32;
+            let mut carry = digit;
+            for b in bytes.iter_mut().rev() {
+                let acc = (*b as u32) * 62 + carry;
+                *b = acc as u8;
+                carry = acc >> 8;
+            }
+            while carry > 0 {
+                bytes.insert(0, carry as u8);
+                carry >>= 8;
+            }
+        }
+        Ok(bytes)
+    }
+
+    // `key` authenticates the beacon; it should be a value both the
+    // publishing `Server` and the fetching `Client` agree on out of band,
+    // e.g. a `handshake::Trust::beacon_key()` shared via passphrase trust.
+    pub fn encode(addr: &SocketAddr, key: &[u8]) -> String {
+        let mut payload = addr_to_bytes(addr);
+        let mac = tag(key, &payload);
+        payload.extend_from_slice(&mac);
+        base62_encode(payload)
+    }
+
+    pub fn decode(token: &str, key: &[u8]) -> Result<SocketAddr, BeaconError> {
+        let bytes = try!(base62_decode(token));
+        if bytes.len() < TAG_LEN + 1 {
+            return Err(BeaconError::Malformed);
+        }
+
+        let split = bytes.len() - TAG_LEN;
+        let (payload, given_tag) = bytes.split_at(split);
+        if given_tag != &tag(key, payload)[..] {
+            return Err(BeaconError::InvalidTag);
+        }
+
+        bytes_to_addr(payload)
+    }
+
+    // Where a published beacon token lives. `File` is the simple case for a
+    // shared filesystem or mounted volume; `Http` does a bare-bones
+    // GET/PUT against a small HTTP endpoint (e.g. a DNS-TXT-backed gateway)
+    // without pulling in a full HTTP client dependency.
+    pub enum Store {
+        File(String),
+        Http(String),
+    }
+
+    impl Store {
+        pub fn publish(&self, token: &str) -> Result<(), BeaconError> {
+            match *self {
+                Store::File(ref path) => {
+                    let mut f = try!(File::create(path).map_err(|e| BeaconError::Io(e.to_string())));
+                    f.write_all(token.as_bytes()).map_err(|e| BeaconError::Io(e.to_string()))
+                }
+                Store::Http(ref url) => put_http(url, token),
+            }
+        }
+
+        pub fn fetch(&self) -> Result<String, BeaconError> {
+            match *self {
+                Store::File(ref path) => {
+                    let mut f = try!(File::open(path).map_err(|e| BeaconError::Io(e.to_string())));
+                    let mut token = String::new();
+                    try!(f.read_to_string(&mut token).map_err(|e| BeaconError::Io(e.to_string())));
+                    Ok(token.trim().to_owned())
+                }
+                Store::Http(ref url) => get_http(url),
+            }
+        }
+    }
+
+    fn split_url(url: &str) -> (String, String) {
+        let rest = url.trim_start_matches("http://");
+        let idx = rest.find('/').unwrap_or(rest.len());
+        let (host_port, path) = rest.split_at(idx);
+        let path = if path.is_empty() { "/" } else { path };
+        (host_port.to_owned(), path.to_owned())
+    }
+
+    fn get_http(url: &str) -> Result<String, BeaconError> {
+        let (host_port, path) = split_url(url);
+        let mut stream = try!(TcpStream::connect(&host_port[..]).map_err(|e| BeaconError::Io(e.to_string())));
+        let request = format!("GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n", path, host_port);
+        try!(stream.write_all(request.as_bytes()).map_err(|e| BeaconError::Io(e.to_string())));
+
+        let mut response = String::new();
+        try!(stream.read_to_string(&mut response).map_err(|e| BeaconError::Io(e.to_string())));
+        let body = response.splitn(2, "\r\n\r\n").nth(1).unwrap_or("");
+        Ok(body.trim().to_owned())
+    }
+
+    fn put_http(url: &str, token: &str) -> Result<(), BeaconError> {
+        let (host_port, path) = split_url(url);
+        let mut stream = try!(TcpStream::connect(&host_port[..]).map_err(|e| BeaconError::Io(e.to_string())));
+        let request = format!("PUT {} HTTP/1.0\r\nHost: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                               path, host_port, token.len(), token);
+        stream.write_all(request.as_bytes()).map_err(|e| BeaconError::Io(e.to_string()))
+    }
+
+    #[cfg(test)]
+    mod test {
+        #[test]
+        fn roundtrip() {
+            use std::net::SocketAddr;
+
+            let key = super::super::crypto::gen_key();
+            let addr: SocketAddr = "203.0.113.7:4242".parse().unwrap();
+
+            let token = super::encode(&addr, &key);
+            let decoded = super::decode(&token, &key).unwrap();
+
+            assert_eq!(decoded, addr);
+        }
+
+        #[test]
+        fn roundtrip_ipv6() {
+            use std::net::SocketAddr;
+
+            let key = super::super::crypto::gen_key();
+            let addr: SocketAddr = "[2001:db8::1]:9000".parse().unwrap();
+
+            let token = super::encode(&addr, &key);
+            let decoded = super::decode(&token, &key).unwrap();
+
+            assert_eq!(decoded, addr);
+        }
+
+        #[test]
+        fn rejects_tag_under_wrong_key() {
+            use std::net::SocketAddr;
+
+            let key = super::super::crypto::gen_key();
+            let other_key = super::super::crypto::gen_key();
+            let addr: SocketAddr = "203.0.113.7:4242".parse().unwrap();
+
+            let token = super::encode(&addr, &key);
+            assert!(super::decode(&token, &other_key).is_err());
+        }
+    }
+}
+
+// Every sealed message is prefixed with this header before being handed to
+// `sendmsg`: a frame type (room for control/keepalive frames later) and the
+// sealed payload length, so `recv` can sanity-check what it pulled off the
+// wire before handing it to `crypto::Handler::open`.
+const FRAME_TYPE_LEN: usize = 1;
+const FRAME_LEN_LEN: usize = 4;
+const FRAME_HEADER_LEN: usize = FRAME_TYPE_LEN + FRAME_LEN_LEN;
+const FRAME_TYPE_DATA: u8 = 0;
+
+#[derive(Debug)]
+pub enum Error {
+    Udt(UdtError),
+    Handshake(handshake::HandshakeError),
+    Beacon(beacon::BeaconError),
+    Crypto(String),
+    Framing(String),
+    NotConnected,
+}
+
+impl From<UdtError> for Error {
+    fn from(e: UdtError) -> Error {
+        Error::Udt(e)
+    }
+}
+
+impl From<handshake::HandshakeError> for Error {
+    fn from(e: handshake::HandshakeError) -> Error {
+        Error::Handshake(e)
+    }
+}
+
+impl From<beacon::BeaconError> for Error {
+    fn from(e: beacon::BeaconError) -> Error {
+        Error::Beacon(e)
+    }
+}
+
+fn new_udt_socket() -> Result<UdtSocket, Error> {
     udt::init();
-    let sock = UdtSocket::new(SocketFamily::AFInet, SocketType::Datagram).unwrap();
-    sock.setsockopt(UdtOpts::UDP_RCVBUF, UDT_BUF_SIZE).unwrap();
-    sock.setsockopt(UdtOpts::UDP_SNDBUF, UDT_BUF_SIZE).unwrap();
-    sock
+    let sock = try!(UdtSocket::new(SocketFamily::AFInet, SocketType::Datagram));
+    try!(sock.setsockopt(UdtOpts::UDP_RCVBUF, UDT_BUF_SIZE));
+    try!(sock.setsockopt(UdtOpts::UDP_SNDBUF, UDT_BUF_SIZE));
+    Ok(sock)
 }
 
-fn send(sock: &UdtSocket, key: &aead::SealingKey, buf: &mut [u8], len: usize) -> Result<(), UdtError> {
-    unimplemented!();
-    // FIXME don't unwrap, create an Error struct that can handle everything
-    // if let Ok(sealed_len) = crypto::seal(buf, len, key) {
-    //     sock.sendmsg(&buf[..sealed_len]).map(|_| ())
-    // } else {
-    //     Err(UdtError {
-    //         err_code: -1,
-    //         err_msg: "encryption failure".into(),
-    //     })
-    // }
+fn send(sock: &UdtSocket, crypto: &mut crypto::Handler, buf: &[u8]) -> Result<(), Error> {
+    if buf.len() > MAX_MESSAGE_SIZE {
+        return Err(Error::Framing("message exceeds MAX_MESSAGE_SIZE".into()));
+    }
+
+    let mut sealed = vec![0u8; buf.len() + crypto.overhead()];
+    sealed[..buf.len()].copy_from_slice(buf);
+    let sealed_len = try!(crypto.seal(&mut sealed, buf.len())
+        .map_err(|_| Error::Crypto("seal failed".into())));
+
+    let mut framed = vec![0u8; FRAME_HEADER_LEN + sealed_len];
+    framed[0] = FRAME_TYPE_DATA;
+    framed[FRAME_TYPE_LEN..FRAME_HEADER_LEN].copy_from_slice(&be_u32(sealed_len as u32));
+    framed[FRAME_HEADER_LEN..].copy_from_slice(&sealed[..sealed_len]);
+
+    try!(sock.sendmsg(&framed));
+    Ok(())
 }
 
-fn recv(sock: &UdtSocket, key: &aead::OpeningKey, buf: &mut [u8]) -> Result<usize, UdtError> {
-    unimplemented!();
-    // let size = try!(sock.recvmsg(buf));
-    // crypto::open(&mut buf[..size], key).map_err(|_| {
-    //     UdtError {
-    //         err_code: -1,
-    //         err_msg: String::from("decryption failure"),
-    //     }
-    // })
+fn recv(sock: &UdtSocket, crypto: &mut crypto::Handler, buf: &mut [u8]) -> Result<usize, Error> {
+    let mut datagram = vec![0u8; MAX_MESSAGE_SIZE + FRAME_HEADER_LEN + crypto.overhead()];
+    let len = try!(sock.recvmsg(&mut datagram));
+
+    if len < FRAME_HEADER_LEN {
+        return Err(Error::Framing("datagram too short to contain a frame header".into()));
+    }
+
+    // payload_len is the *sealed* length, which runs `crypto.overhead()`
+    // bytes past MAX_MESSAGE_SIZE for a maximum-size plaintext -- matches
+    // the ceiling `crypto::Handler::open` applies below and the size
+    // `datagram` is allocated to above.
+    let payload_len = read_be_u32(&datagram[FRAME_TYPE_LEN..FRAME_HEADER_LEN]) as usize;
+    if payload_len > MAX_MESSAGE_SIZE + crypto.overhead() || FRAME_HEADER_LEN + payload_len > len {
+        return Err(Error::Framing("frame payload length out of bounds".into()));
+    }
+
+    let plaintext_len = try!(crypto.open(&mut datagram[FRAME_HEADER_LEN..FRAME_HEADER_LEN + payload_len])
+        .map_err(Error::Crypto));
+
+    if plaintext_len > buf.len() {
+        return Err(Error::Framing("caller buffer too small for decrypted message".into()));
+    }
+    buf[..plaintext_len].copy_from_slice(&datagram[FRAME_HEADER_LEN..FRAME_HEADER_LEN + plaintext_len]);
+    Ok(plaintext_len)
+}
+
+fn be_u32(n: u32) -> [u8; 4] {
+    [(n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8, n as u8]
+}
+
+fn read_be_u32(bytes: &[u8]) -> u32 {
+    ((bytes[0] as u32) << 24) | ((bytes[1] as u32) << 16) | ((bytes[2] as u32) << 8) | bytes[3] as u32
 }
 
 pub struct PortRange {
@@ -270,57 +1144,86 @@ pub struct PortRange {
 }
 
 pub trait Transceiver {
-    fn send(&self, buf: &[u8]) -> Result<(), UdtError>;
-    fn recv(&self, buf: &mut [u8]) -> Result<usize, UdtError>;
-    fn close(&self) -> Result<(), UdtError>;
+    fn send(&mut self, buf: &[u8]) -> Result<(), Error>;
+    fn recv(&mut self, buf: &mut [u8]) -> Result<usize, Error>;
+    fn close(&self) -> Result<(), Error>;
 }
 
 pub struct Server {
     pub ip_addr: IpAddr,
     pub port: u16,
-    crypto: crypto::Handler,
+    identity: handshake::Identity,
+    trust: handshake::Trust,
     sock: UdtSocket,
 }
 
 pub struct Client {
     addr: SocketAddr,
     sock: UdtSocket,
-    crypto: crypto::Handler,
+    identity: handshake::Identity,
+    trust: handshake::Trust,
+    crypto: Option<crypto::Handler>,
 }
 
-pub struct ServerConnection<'a> {
-    crypto: &'a crypto::Handler,
+pub struct ServerConnection {
+    crypto: crypto::Handler,
     sock: UdtSocket,
 }
 
 impl Client {
-    pub fn new(addr: SocketAddr, key: &[u8]) -> Client {
-        let sock = new_udt_socket();
-        Client {
+    pub fn new(addr: SocketAddr, identity: handshake::Identity, trust: handshake::Trust) -> Result<Client, Error> {
+        let sock = try!(new_udt_socket());
+        Ok(Client {
             addr: addr,
             sock: sock,
-            crypto: crypto::Handler::new(key),
-        }
+            identity: identity,
+            trust: trust,
+            crypto: None,
+        })
     }
 
-    pub fn connect(&self) -> Result<(), UdtError> {
-        self.sock.connect(self.addr)
+    pub fn connect(&mut self) -> Result<(), Error> {
+        try!(self.sock.connect(self.addr));
+        let hs = handshake::Handshake::new(&self.identity, &self.trust, true);
+        self.crypto = Some(try!(hs.run(&self.sock)));
+        Ok(())
+    }
+
+    // Resolves a beacon token fetched from `store` into a `Server`'s current
+    // address instead of requiring a fixed, pre-known `SocketAddr`. Only
+    // works against a `Server` using `Trust::SharedSecret`, since that's
+    // the only `trust` that can produce the key the beacon was published
+    // under.
+    pub fn from_beacon(store: &beacon::Store,
+                        identity: handshake::Identity,
+                        trust: handshake::Trust)
+                        -> Result<Client, Error> {
+        let key = try!(trust.beacon_key());
+        let token = try!(store.fetch());
+        let addr = try!(beacon::decode(&token, &key));
+        Client::new(addr, identity, trust)
     }
 }
 
 impl Transceiver for Client {
-    fn send(&self, buf: &[u8]) -> Result<(), UdtError> {
-        unimplemented!();
-        // send(&self.sock, &self.sealing_key, buf)
+    fn send(&mut self, buf: &[u8]) -> Result<(), Error> {
+        let sock = &self.sock;
+        match self.crypto {
+            Some(ref mut crypto) => send(sock, crypto, buf),
+            None => Err(Error::NotConnected),
+        }
     }
 
-    fn recv(&self, buf: &mut [u8]) -> Result<usize, UdtError> {
-        unimplemented!();
-        // recv(&self.sock, &self.opening_key, buf)
+    fn recv(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let sock = &self.sock;
+        match self.crypto {
+            Some(ref mut crypto) => recv(sock, crypto, buf),
+            None => Err(Error::NotConnected),
+        }
     }
 
-    fn close(&self) -> Result<(), UdtError> {
-        self.sock.close()
+    fn close(&self) -> Result<(), Error> {
+        self.sock.close().map_err(Error::from)
     }
 }
 
@@ -334,50 +1237,71 @@ impl Server {
         Err(())
     }
 
-    pub fn new(ip_addr: IpAddr, port: u16, key: &[u8]) -> Server {
-        let sock = new_udt_socket();
-        sock.bind(SocketAddr::new(ip_addr, port)).unwrap();
-        Server {
+    pub fn new(ip_addr: IpAddr, port: u16, identity: handshake::Identity, trust: handshake::Trust) -> Result<Server, Error> {
+        let sock = try!(new_udt_socket());
+        try!(sock.bind(SocketAddr::new(ip_addr, port)));
+        Ok(Server {
             sock: sock,
             ip_addr: ip_addr,
             port: port,
-            crypto: crypto::Handler::new(key),
-        }
+            identity: identity,
+            trust: trust,
+        })
     }
 
     pub fn listen(&self) -> Result<(), UdtError> {
         self.sock.listen(2)
     }
 
-    pub fn accept(&self) -> Result<ServerConnection, UdtError> {
-        self.sock.accept().map(|(sock, _)| {
-            ServerConnection {
-                crypto: &self.crypto,
-                sock: sock,
+    pub fn publish_beacon(&self, store: &beacon::Store) -> Result<String, Error> {
+        let key = try!(self.trust.beacon_key());
+        let token = beacon::encode(&SocketAddr::new(self.ip_addr, self.port), &key);
+        try!(store.publish(&token));
+        Ok(token)
+    }
+
+    // Republishes our beacon to `store` every `interval` from a background
+    // thread, so a `Client` re-fetching it always finds a recent endpoint.
+    pub fn run_beacon(&self, store: beacon::Store, interval: Duration) -> Result<thread::JoinHandle<()>, Error> {
+        let key = try!(self.trust.beacon_key());
+        let addr = SocketAddr::new(self.ip_addr, self.port);
+        Ok(thread::spawn(move || {
+            loop {
+                let token = beacon::encode(&addr, &key);
+                let _ = store.publish(&token);
+                thread::sleep(interval);
             }
+        }))
+    }
+
+    pub fn accept(&self) -> Result<ServerConnection, Error> {
+        let (sock, _) = try!(self.sock.accept());
+        let hs = handshake::Handshake::new(&self.identity, &self.trust, false);
+        let crypto = try!(hs.run(&sock));
+        Ok(ServerConnection {
+            crypto: crypto,
+            sock: sock,
         })
     }
 }
 
-impl<'a> ServerConnection<'a> {
+impl ServerConnection {
     pub fn getpeer(&self) -> Result<SocketAddr, UdtError> {
         self.sock.getpeername()
     }
 }
 
-impl<'a> Transceiver for ServerConnection<'a> {
-    fn send(&self, buf: &[u8]) -> Result<(), UdtError> {
-        unimplemented!();
-        // send(&self.sock, self.key, buf)
+impl Transceiver for ServerConnection {
+    fn send(&mut self, buf: &[u8]) -> Result<(), Error> {
+        send(&self.sock, &mut self.crypto, buf)
     }
 
-    fn recv(&self, buf: &mut [u8]) -> Result<usize, UdtError> {
-        unimplemented!();
-        // recv(&self.sock, self.key, buf)
+    fn recv(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        recv(&self.sock, &mut self.crypto, buf)
     }
 
-    fn close(&self) -> Result<(), UdtError> {
-        self.sock.close()
+    fn close(&self) -> Result<(), Error> {
+        self.sock.close().map_err(Error::from)
     }
 }
 